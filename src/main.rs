@@ -20,13 +20,20 @@ mod renderer;
 async fn main() {
     env_logger::init_from_env(Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"));
 
-    if let Err(e) = render().await {
-        error!("{:?}", e);
-        process::exit(1);
+    match render().await {
+        Ok(true) => {}
+        Ok(false) => {
+            error!("Render finished with non-critical failures, see the summary above");
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            process::exit(1);
+        }
     }
 }
 
-async fn render() -> Result<(), Error> {
+async fn render() -> Result<bool, Error> {
     let context = RenderContext::from_json(io::stdin())?;
     let config: ConfluenceConfig = context
         .config
@@ -49,10 +56,13 @@ async fn render() -> Result<(), Error> {
             );
         }
 
-        confluence_renderer.render(context).await?;
+        let report = confluence_renderer.render(context).await?;
+        println!("{}", report);
+
+        Ok(!report.has_failures())
     } else {
-        info!("Confluence renderer is disabled")
-    }
+        info!("Confluence renderer is disabled");
 
-    Ok(())
+        Ok(true)
+    }
 }
@@ -2,8 +2,8 @@ use crate::client::EnhancedSession;
 use colored::*;
 use confluence::rpser::xml;
 use confluence::AttachmentRequest;
-use confluence::{Error as ConfluenceError, Page, PageSummary, Session, UpdatePage};
-use futures::future;
+use confluence::{Attachment, Error as ConfluenceError, Page, PageSummary, Session, UpdatePage};
+use futures::stream::{FuturesUnordered, StreamExt};
 use mdbook::book::Chapter;
 use mdbook::errors::Error as MdBookError;
 use mdbook::renderer::RenderContext;
@@ -12,20 +12,182 @@ use mdbook::BookItem;
 use mime_guess::MimeGuess;
 use pulldown_cmark::{Event, Tag};
 use pulldown_cmark_to_cmark::fmt::cmark;
+use rand::Rng;
 use regex::Regex;
 use semver::Version;
+use sha2::{Digest, Sha256};
 use std::ffi::OsStr;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use std::future::Future;
+use std::io;
 use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
 use unicode_segmentation::UnicodeSegmentation;
 
+/// Default number of Confluence page/attachment operations allowed in flight at once.
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+/// Default number of times a transient Confluence error is retried before giving up.
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Default base delay for the exponential backoff between retries.
+const DEFAULT_BASE_DELAY_MS: u64 = 250;
+
+fn default_max_concurrency() -> Option<usize> {
+    Some(DEFAULT_MAX_CONCURRENCY)
+}
+
+/// best-effort check for whether a Confluence error is transient (timeout, 429, 5xx) and
+/// worth retrying
+fn is_transient(error: &ConfluenceError) -> bool {
+    let message = format!("{:?}", error).to_lowercase();
+
+    ["429", "500", "502", "503", "504", "timed out", "timeout", "connection reset"]
+        .iter()
+        .any(|needle| message.contains(needle))
+}
+
+/// exponential backoff with jitter
+fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let exponential = base_delay.saturating_mul(1u32 << (attempt - 1).min(16));
+    let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..=exponential.as_millis() as u64 / 2 + 1));
+
+    exponential + jitter
+}
+
+fn to_hex_digest(bytes: impl AsRef<[u8]>) -> String {
+    bytes
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// digests a page's rendered title and body
+fn hash_page_content(title: &str, body: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(title.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(body.as_bytes());
+
+    to_hex_digest(hasher.finalize())
+}
+
+fn hash_marker_comment(hash: &str) -> String {
+    format!("<!-- mdbook-confluence-hash:{} -->", hash)
+}
+
+/// pulls the hash marker out of a page's content, if present
+fn existing_page_hash(content: &str) -> Option<&str> {
+    lazy_static! {
+        static ref HASH_MARKER: Regex =
+            Regex::new(r"<!--\s*mdbook-confluence-hash:([0-9a-f]+)\s*-->").unwrap();
+    }
+
+    HASH_MARKER
+        .captures(content)
+        .and_then(|captures| captures.get(1))
+        .map(|m| m.as_str())
+}
+
+/// digests a local file's contents
+fn hash_file(path: &std::path::Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(&std::fs::read(path)?);
+
+    Ok(to_hex_digest(hasher.finalize()))
+}
+
+fn attachment_hash_comment(hash: &str) -> String {
+    format!("mdbook-confluence-sha256:{}", hash)
+}
+
+/// pulls the content hash out of an attachment's comment, if present
+fn existing_attachment_hash(comment: &str) -> Option<&str> {
+    comment.strip_prefix("mdbook-confluence-sha256:")
+}
+
 pub static RENDERER_NAME: &str = "confluence";
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageStatus {
+    Created,
+    Updated,
+    Skipped,
+    Deleted,
+}
+
+impl PageStatus {
+    fn label(self) -> ColoredString {
+        match self {
+            PageStatus::Created => "Created".green(),
+            PageStatus::Updated => "Updated".yellow(),
+            PageStatus::Skipped => "Skipped".cyan(),
+            PageStatus::Deleted => "Deleted".red(),
+        }
+    }
+}
+
+struct PageOutcome {
+    status: PageStatus,
+    title: String,
+    url: String,
+    parent_page: ParentPage,
+}
+
+/// tallies what happened across a whole render, so a broken partial-publish can be detected
+/// even though we keep rendering the rest of the book
+#[derive(Debug, Default, Clone)]
+pub struct RunReport {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    pub failures: Vec<String>,
+}
+
+impl RunReport {
+    fn record(&mut self, status: PageStatus) {
+        match status {
+            PageStatus::Created => self.created += 1,
+            PageStatus::Updated => self.updated += 1,
+            PageStatus::Skipped => self.skipped += 1,
+            PageStatus::Deleted => self.deleted += 1,
+        }
+    }
+
+    /// true if any page or attachment failed to publish, even though the rest of the book
+    /// finished rendering
+    pub fn has_failures(&self) -> bool {
+        !self.failures.is_empty()
+    }
+}
+
+impl Display for RunReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Confluence render summary:")?;
+        writeln!(f, "  {:<10} {}", "Created", self.created)?;
+        writeln!(f, "  {:<10} {}", "Updated", self.updated)?;
+        writeln!(f, "  {:<10} {}", "Skipped", self.skipped)?;
+        writeln!(f, "  {:<10} {}", "Deleted", self.deleted)?;
+        write!(f, "  {:<10} {}", "Failed", self.failures.len())?;
+
+        if self.has_failures() {
+            write!(f, "\n\nFailures:")?;
+            for failure in &self.failures {
+                write!(f, "\n  - {}", failure)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct ConfluenceConfig {
     pub enabled: bool,
@@ -34,6 +196,25 @@ pub struct ConfluenceConfig {
     pub password: String,
     pub title_prefix: Option<String>,
     pub root_page: i64,
+    pub max_concurrency: Option<usize>,
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+}
+
+impl Default for ConfluenceConfig {
+    fn default() -> Self {
+        ConfluenceConfig {
+            enabled: bool::default(),
+            url: String::default(),
+            username: String::default(),
+            password: String::default(),
+            title_prefix: None,
+            root_page: i64::default(),
+            max_concurrency: default_max_concurrency(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay_ms: DEFAULT_BASE_DELAY_MS,
+        }
+    }
 }
 
 impl ConfluenceConfig {
@@ -64,17 +245,25 @@ trait AyncRenderer {
         parent: Arc<ParentPage>,
         existing_page_id: Option<i64>,
         root_path: Arc<PathBuf>,
-    ) -> Pin<Box<dyn Future<Output = Result<String, Error>>>>;
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>>>>;
 }
 
 struct InternalRenderer {
     session: Session,
     server_version: Version,
     config: ConfluenceConfig,
+    concurrency_limit: Option<Semaphore>,
+    report: Mutex<RunReport>,
 }
 
 impl ConfluenceRenderer {
     pub async fn new(config: ConfluenceConfig) -> Result<ConfluenceRenderer, Error> {
+        if config.max_concurrency == Some(0) {
+            return Err(Error::Error(
+                "max_concurrency must be greater than 0".into(),
+            ));
+        }
+
         let session = Session::login(
             &config.url.clone(),
             &config.username.clone(),
@@ -86,20 +275,24 @@ impl ConfluenceRenderer {
 
         info!("Logged into Confluence. Version: {}", server_version);
 
+        let concurrency_limit = config.max_concurrency.map(Semaphore::new);
+
         Ok(ConfluenceRenderer {
             internal: Arc::new(InternalRenderer {
                 session,
                 server_version,
                 config,
+                concurrency_limit,
+                report: Mutex::new(RunReport::default()),
             }),
         })
     }
 
-    pub async fn render(&self, ctx: RenderContext) -> Result<(), Error> {
+    pub async fn render(&self, ctx: RenderContext) -> Result<RunReport, Error> {
+        let root_page = self.config().root_page;
         let parent_page = self
             .internal
-            .session
-            .get_page_by_id(self.config().root_page)
+            .with_permit(self.internal.with_retry(|| self.internal.session.get_page_by_id(root_page)))
             .await?;
 
         self.internal
@@ -109,7 +302,9 @@ impl ConfluenceRenderer {
                 parent_page.into(),
                 Arc::new(ctx.root.join(ctx.config.book.src)),
             )
-            .await
+            .await?;
+
+        Ok(self.internal.report.lock().unwrap().clone())
     }
 
     pub async fn logout(self) -> Result<bool, Error> {
@@ -126,6 +321,63 @@ impl ConfluenceRenderer {
 }
 
 impl InternalRenderer {
+    /// runs `fut` after acquiring a slot in the shared concurrency limit
+    async fn with_permit<F, T>(&self, fut: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let _permit = match &self.concurrency_limit {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("concurrency semaphore should never be closed"),
+            ),
+            None => None,
+        };
+
+        fut.await
+    }
+
+    /// retries `request` with exponential backoff on transient errors, up to `config.max_retries` times
+    async fn with_retry<T, F, Fut>(&self, mut request: F) -> Result<T, ConfluenceError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ConfluenceError>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match request().await {
+                Ok(value) => return Ok(value),
+                Err(error) if attempt < self.config.max_retries && is_transient(&error) => {
+                    attempt += 1;
+                    let delay = backoff_delay(
+                        Duration::from_millis(self.config.base_delay_ms),
+                        attempt,
+                    );
+
+                    warn!(
+                        "Transient Confluence error (attempt {}/{}), retrying in {:?}: {:?}",
+                        attempt, self.config.max_retries, delay, error
+                    );
+
+                    tokio::time::sleep(delay).await;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn record_success(&self, status: PageStatus) {
+        self.report.lock().unwrap().record(status);
+    }
+
+    /// records a failure in the run report
+    fn record_failure(&self, error: &Error) {
+        self.report.lock().unwrap().failures.push(error.to_string());
+    }
+
     /// finds the existing page if we have one, or creates one without any content
     async fn get_existing_page(
         &self,
@@ -144,19 +396,25 @@ impl InternalRenderer {
                     parent_id: Some(parent.id),
                 };
 
-                self.session.store_page(new_page).await.map_err(Into::into)
+                self.with_permit(self.with_retry(|| self.session.store_page(new_page.clone())))
+                    .await
+                    .map_err(Into::into)
             }
-            Some(id) => self.session.get_page_by_id(id).await.map_err(Into::into),
+            Some(id) => self
+                .with_permit(self.with_retry(|| self.session.get_page_by_id(id)))
+                .await
+                .map_err(Into::into),
         }
     }
 
+    /// builds the Confluence content for `chapter`, embedding a content-hash marker
     async fn create_page_content(
         &self,
         chapter: &Chapter,
-        existing_page: Page,
+        existing_page: &Page,
         parent: &ParentPage,
         root_path: &PathBuf,
-    ) -> Result<UpdatePage, Error> {
+    ) -> Result<(UpdatePage, String), Error> {
         let mut events = vec![];
         let mut last_image = None;
         let mut chapter_path = chapter.path.clone();
@@ -202,14 +460,26 @@ impl InternalRenderer {
         cmark(events.iter(), &mut buf, None)
             .map_err(|err| Error::Error(format!("Markdown serialization failed: {}", err)))?;
 
-        Ok(UpdatePage {
-            id: Some(existing_page.id),
-            space: parent.space.clone(),
-            title: self.config.chapter_title(chapter),
-            content: self.to_page_content(&buf),
-            version: Some(existing_page.version),
-            parent_id: Some(parent.id),
-        })
+        let title = self.config.chapter_title(chapter);
+        let content_hash = hash_page_content(&title, &buf);
+
+        let content = format!(
+            "{}\n{}",
+            self.to_page_content(&buf),
+            hash_marker_comment(&content_hash)
+        );
+
+        Ok((
+            UpdatePage {
+                id: Some(existing_page.id),
+                space: parent.space.clone(),
+                title,
+                content,
+                version: Some(existing_page.version),
+                parent_id: Some(parent.id),
+            },
+            content_hash,
+        ))
     }
 
     async fn upload_image(
@@ -226,20 +496,39 @@ impl InternalRenderer {
 
         // check for scheme: links and don't modify them
         if !SCHEME_LINK.is_match(&image_url) {
-            info!("Attempting to upload file: {}", image_url);
             // try to find our file to upload on disk
             let path = root_path.join(image_url);
+            let file_name = path.file_name().and_then(OsStr::to_str).unwrap_or("");
+
+            let file_hash = match hash_file(&path) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    let error = Error::Error(format!(
+                        "Failed to read file to upload: {}: {:?}",
+                        path.display(),
+                        e
+                    ));
+                    self.record_failure(&error);
+                    error!("{}", error);
+                    return None;
+                }
+            };
+
+            if let Some(existing_url) = self.find_matching_attachment(page_id, file_name, &file_hash).await {
+                info!("{} unchanged, reusing existing attachment: {}", file_name, existing_url);
+                return Some(existing_url);
+            }
+
+            info!("Attempting to upload file: {}", image_url);
+            let attachment = AttachmentRequest::new(
+                file_name,
+                MimeGuess::from_path(&path).first_or_octet_stream(),
+                title,
+                Some(attachment_hash_comment(&file_hash)),
+            );
             let result = self
-                .session
-                .add_file(
-                    page_id,
-                    AttachmentRequest::new(
-                        path.file_name().and_then(OsStr::to_str).unwrap_or(""),
-                        MimeGuess::from_path(&path).first_or_octet_stream(),
-                        title,
-                        None,
-                    ),
-                    &path,
+                .with_permit(
+                    self.with_retry(|| self.session.add_file(page_id, attachment.clone(), &path)),
                 )
                 .await
                 .map(|a| match a.url {
@@ -248,7 +537,11 @@ impl InternalRenderer {
                         Some(file_url)
                     }
                     None => {
-                        error!("Uploaded an attachment but couldn't find a url for it");
+                        let error = Error::Error(
+                            "Uploaded an attachment but couldn't find a url for it".into(),
+                        );
+                        self.record_failure(&error);
+                        error!("{}", error);
                         None
                     }
                 });
@@ -256,7 +549,9 @@ impl InternalRenderer {
             match result {
                 Ok(url) => url,
                 Err(e) => {
-                    error!("Attempted to upload file but hit an error: {:?}", e);
+                    let error = Error::from(e);
+                    self.record_failure(&error);
+                    error!("Attempted to upload file but hit an error: {}", error);
                     None
                 }
             }
@@ -265,6 +560,36 @@ impl InternalRenderer {
         }
     }
 
+    /// looks for an existing attachment with a matching filename and content digest
+    async fn find_matching_attachment(
+        &self,
+        page_id: i64,
+        file_name: &str,
+        file_hash: &str,
+    ) -> Option<String> {
+        let attachments: Vec<Attachment> = match self
+            .with_permit(self.with_retry(|| self.session.get_attachments(page_id)))
+            .await
+        {
+            Ok(attachments) => attachments,
+            Err(e) => {
+                warn!(
+                    "Failed to list existing attachments for page {}, uploading anyway: {:?}",
+                    page_id, e
+                );
+                return None;
+            }
+        };
+
+        attachments
+            .into_iter()
+            .find(|attachment| {
+                attachment.title == file_name
+                    && existing_attachment_hash(&attachment.comment) == Some(file_hash)
+            })
+            .and_then(|attachment| attachment.url)
+    }
+
     fn to_page_content(&self, markdown: &str) -> String {
         format!(
             r#"<ac:structured-macro ac:name="markdown" ac:schema-version="1" ac:macro-id="249327eb-2c99-42ca-a7a7-487e1c0c7e04">
@@ -314,10 +639,12 @@ impl AyncRenderer for Arc<InternalRenderer> {
         root_path: Arc<PathBuf>,
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
         Box::pin(async move {
-            let mut children = self.session.get_children(parent_page.id).await?;
+            let mut children = self
+                .with_permit(self.with_retry(|| self.session.get_children(parent_page.id)))
+                .await?;
 
             let parent_page = Arc::new(parent_page);
-            let mut child_futures = vec![];
+            let mut child_futures = FuturesUnordered::new();
 
             for item in items.into_iter() {
                 if let BookItem::Chapter(chapter) = item {
@@ -339,25 +666,39 @@ impl AyncRenderer for Arc<InternalRenderer> {
                 }
             }
 
-            // join our child futures and render the results
-            for result in future::join_all(child_futures).await {
-                match result {
-                    Ok(success) => info!("{}", success),
-                    Err(e) => error!("{}", e),
+            // drain our child futures as each one completes, rather than waiting for all of
+            // them, so results surface as soon as they're ready while the semaphore keeps the
+            // number of concurrent Confluence requests bounded. render_page records its own
+            // successes, but leaves failures (its own, or ones escaping its nested
+            // render_group call) for us to record and log here instead.
+            while let Some(result) = child_futures.next().await {
+                if let Err(e) = result {
+                    self.record_failure(&e);
+                    error!("{}", e);
                 }
             }
 
             // any remaining children were probably deleted from the book so delete them here
             for deleted_child in children {
                 let deleted_id = deleted_child.id;
-                match self.session.remove_page(deleted_id).await {
-                    Ok(_) => info!(
-                        "{} page: '{}' {}",
-                        "Deleted".red(),
-                        deleted_child.title,
-                        deleted_child.url
-                    ),
-                    Err(e) => error!("{:?}", e),
+                match self
+                    .with_permit(self.with_retry(|| self.session.remove_page(deleted_id)))
+                    .await
+                {
+                    Ok(_) => {
+                        self.record_success(PageStatus::Deleted);
+                        info!(
+                            "{} page: '{}' {}",
+                            PageStatus::Deleted.label(),
+                            deleted_child.title,
+                            deleted_child.url
+                        );
+                    }
+                    Err(e) => {
+                        let error = Error::from(e);
+                        self.record_failure(&error);
+                        error!("{}", error);
+                    }
                 }
             }
 
@@ -371,31 +712,66 @@ impl AyncRenderer for Arc<InternalRenderer> {
         parent: Arc<ParentPage>,
         existing_page_id: Option<i64>,
         root_path: Arc<PathBuf>,
-    ) -> Pin<Box<dyn Future<Output = Result<String, Error>>>> {
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>>>> {
         Box::pin(async move {
-            let existing_page = self
-                .get_existing_page(&chapter, existing_page_id, &parent)
-                .await?;
+            let page_title = self.config.chapter_title(&chapter);
+
+            let result: Result<PageOutcome, Error> = async {
+                let existing_page = self
+                    .get_existing_page(&chapter, existing_page_id, &parent)
+                    .await?;
+                let previous_hash =
+                    existing_page_hash(&existing_page.content).map(str::to_string);
+
+                let (update, content_hash) = self
+                    .create_page_content(&chapter, &existing_page, &parent, &root_path)
+                    .await?;
+
+                // if nothing in the rendered content changed since the last run, skip the
+                // store entirely rather than bumping the Confluence version history for
+                // no reason
+                if existing_page_id.is_some()
+                    && previous_hash.as_deref() == Some(content_hash.as_str())
+                {
+                    return Ok(PageOutcome {
+                        status: PageStatus::Skipped,
+                        title: existing_page.title.clone(),
+                        url: existing_page.url.clone(),
+                        parent_page: existing_page.into(),
+                    });
+                }
 
-            let new_page = self
-                .create_page_content(&chapter, existing_page, &parent, &root_path)
-                .await?;
-            let new_page = self.session.store_page(new_page).await?;
-            let success = format!(
-                "{} '{}' {}",
-                if existing_page_id.is_some() {
-                    "Updated".yellow()
-                } else {
-                    "Created".green()
-                },
-                new_page.title,
-                new_page.url
-            );
+                let new_page = self
+                    .with_permit(self.with_retry(|| self.session.store_page(update.clone())))
+                    .await?;
 
-            self.render_group(chapter.sub_items, new_page.into(), root_path.clone())
-                .await?;
+                Ok(PageOutcome {
+                    status: if existing_page_id.is_some() {
+                        PageStatus::Updated
+                    } else {
+                        PageStatus::Created
+                    },
+                    title: new_page.title.clone(),
+                    url: new_page.url.clone(),
+                    parent_page: new_page.into(),
+                })
+            }
+            .await
+            .map_err(|e| Error::Error(format!("'{}': {}", page_title, e)));
 
-            Ok(success)
+            let outcome = match result {
+                Ok(outcome) => {
+                    self.record_success(outcome.status);
+                    info!("{} '{}' {}", outcome.status.label(), outcome.title, outcome.url);
+                    outcome
+                }
+                // left unrecorded here; the caller's render_group drain loop records and logs
+                // it, which is also where errors escaping our own nested render_group call end up
+                Err(e) => return Err(e),
+            };
+
+            self.render_group(chapter.sub_items, outcome.parent_page, root_path.clone())
+                .await
         })
     }
 }